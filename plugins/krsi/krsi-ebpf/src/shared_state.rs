@@ -16,9 +16,9 @@ limitations under the License.
 */
 
 use aya_ebpf::{
-    helpers::bpf_get_smp_processor_id,
+    helpers::{bpf_get_smp_processor_id, bpf_ktime_get_boot_ns, bpf_ktime_get_ns},
     macros::map,
-    maps::{Array, RingBuf},
+    maps::{Array, PerCpuArray, RingBuf},
 };
 use krsi_common::flags::{FeatureFlags, OpFlags};
 
@@ -36,8 +36,170 @@ static FEATURE_FLAGS: u8 = 0;
 #[no_mangle]
 static OP_FLAGS: u64 = 0;
 
-#[map]
-static EVENTS: RingBuf = RingBuf::with_byte_size(128 * 4096, 0); // 128 pages = 256KB
+// Selects which clock source event_timestamp() derives its timestamps from.
+// Set in userspace at load time, read volatilely like FEATURE_FLAGS/OP_FLAGS.
+#[no_mangle]
+static CLOCK_SOURCE: u8 = 0;
+
+// `bpf_ringbuf_reserve` takes ARG_CONST_MAP_PTR: the map has to be known to
+// the verifier at load time, not resolved dynamically through a lookup (so
+// an `ArrayOfMaps<RingBuf>` keyed by CPU id, while it loads, can't actually
+// be reserved into). Instead declare a fixed table of per-CPU ring buffers
+// and pick one with a `match` on the CPU id, which the verifier can inline.
+// `MAX_CPU_SLOTS` bounds the table; CPUs beyond it share a slot round-robin.
+// Userspace sizes each map's byte size at load time (defaulting otherwise to
+// one page), and only needs to size the first `num_cpus` of them generously.
+const MAX_CPU_SLOTS: u32 = 128;
+const DEFAULT_RINGBUF_BYTE_SIZE: u32 = 4096; // 1 page
+
+macro_rules! percpu_ringbufs {
+    ($($name:ident = $idx:literal),+ $(,)?) => {
+        $(
+            #[map]
+            static $name: RingBuf = RingBuf::with_byte_size(DEFAULT_RINGBUF_BYTE_SIZE, 0);
+        )+
+
+        fn ringbuf_for_cpu_slot(slot: u32) -> &'static RingBuf {
+            match slot {
+                $($idx => &$name,)+
+                // Unreachable: the only caller passes `cpu_id % MAX_CPU_SLOTS`,
+                // which always falls within the exhaustively-listed range above.
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+percpu_ringbufs! {
+    EVENTS_0 = 0,
+    EVENTS_1 = 1,
+    EVENTS_2 = 2,
+    EVENTS_3 = 3,
+    EVENTS_4 = 4,
+    EVENTS_5 = 5,
+    EVENTS_6 = 6,
+    EVENTS_7 = 7,
+    EVENTS_8 = 8,
+    EVENTS_9 = 9,
+    EVENTS_10 = 10,
+    EVENTS_11 = 11,
+    EVENTS_12 = 12,
+    EVENTS_13 = 13,
+    EVENTS_14 = 14,
+    EVENTS_15 = 15,
+    EVENTS_16 = 16,
+    EVENTS_17 = 17,
+    EVENTS_18 = 18,
+    EVENTS_19 = 19,
+    EVENTS_20 = 20,
+    EVENTS_21 = 21,
+    EVENTS_22 = 22,
+    EVENTS_23 = 23,
+    EVENTS_24 = 24,
+    EVENTS_25 = 25,
+    EVENTS_26 = 26,
+    EVENTS_27 = 27,
+    EVENTS_28 = 28,
+    EVENTS_29 = 29,
+    EVENTS_30 = 30,
+    EVENTS_31 = 31,
+    EVENTS_32 = 32,
+    EVENTS_33 = 33,
+    EVENTS_34 = 34,
+    EVENTS_35 = 35,
+    EVENTS_36 = 36,
+    EVENTS_37 = 37,
+    EVENTS_38 = 38,
+    EVENTS_39 = 39,
+    EVENTS_40 = 40,
+    EVENTS_41 = 41,
+    EVENTS_42 = 42,
+    EVENTS_43 = 43,
+    EVENTS_44 = 44,
+    EVENTS_45 = 45,
+    EVENTS_46 = 46,
+    EVENTS_47 = 47,
+    EVENTS_48 = 48,
+    EVENTS_49 = 49,
+    EVENTS_50 = 50,
+    EVENTS_51 = 51,
+    EVENTS_52 = 52,
+    EVENTS_53 = 53,
+    EVENTS_54 = 54,
+    EVENTS_55 = 55,
+    EVENTS_56 = 56,
+    EVENTS_57 = 57,
+    EVENTS_58 = 58,
+    EVENTS_59 = 59,
+    EVENTS_60 = 60,
+    EVENTS_61 = 61,
+    EVENTS_62 = 62,
+    EVENTS_63 = 63,
+    EVENTS_64 = 64,
+    EVENTS_65 = 65,
+    EVENTS_66 = 66,
+    EVENTS_67 = 67,
+    EVENTS_68 = 68,
+    EVENTS_69 = 69,
+    EVENTS_70 = 70,
+    EVENTS_71 = 71,
+    EVENTS_72 = 72,
+    EVENTS_73 = 73,
+    EVENTS_74 = 74,
+    EVENTS_75 = 75,
+    EVENTS_76 = 76,
+    EVENTS_77 = 77,
+    EVENTS_78 = 78,
+    EVENTS_79 = 79,
+    EVENTS_80 = 80,
+    EVENTS_81 = 81,
+    EVENTS_82 = 82,
+    EVENTS_83 = 83,
+    EVENTS_84 = 84,
+    EVENTS_85 = 85,
+    EVENTS_86 = 86,
+    EVENTS_87 = 87,
+    EVENTS_88 = 88,
+    EVENTS_89 = 89,
+    EVENTS_90 = 90,
+    EVENTS_91 = 91,
+    EVENTS_92 = 92,
+    EVENTS_93 = 93,
+    EVENTS_94 = 94,
+    EVENTS_95 = 95,
+    EVENTS_96 = 96,
+    EVENTS_97 = 97,
+    EVENTS_98 = 98,
+    EVENTS_99 = 99,
+    EVENTS_100 = 100,
+    EVENTS_101 = 101,
+    EVENTS_102 = 102,
+    EVENTS_103 = 103,
+    EVENTS_104 = 104,
+    EVENTS_105 = 105,
+    EVENTS_106 = 106,
+    EVENTS_107 = 107,
+    EVENTS_108 = 108,
+    EVENTS_109 = 109,
+    EVENTS_110 = 110,
+    EVENTS_111 = 111,
+    EVENTS_112 = 112,
+    EVENTS_113 = 113,
+    EVENTS_114 = 114,
+    EVENTS_115 = 115,
+    EVENTS_116 = 116,
+    EVENTS_117 = 117,
+    EVENTS_118 = 118,
+    EVENTS_119 = 119,
+    EVENTS_120 = 120,
+    EVENTS_121 = 121,
+    EVENTS_122 = 122,
+    EVENTS_123 = 123,
+    EVENTS_124 = 124,
+    EVENTS_125 = 125,
+    EVENTS_126 = 126,
+    EVENTS_127 = 127
+}
 
 pub fn auxiliary_buffer() -> Option<&'static mut crate::auxbuf::AuxiliaryBuffer> {
     let cpu_id = unsafe { bpf_get_smp_processor_id() };
@@ -47,13 +209,53 @@ pub fn auxiliary_buffer() -> Option<&'static mut crate::auxbuf::AuxiliaryBuffer>
 }
 
 pub fn events_ringbuf() -> &'static RingBuf {
-    &EVENTS
+    let cpu_id = unsafe { bpf_get_smp_processor_id() };
+    ringbuf_for_cpu_slot(cpu_id % MAX_CPU_SLOTS)
 }
 
 pub fn boot_time() -> u64 {
     unsafe { core::ptr::read_volatile(&BOOT_TIME) }
 }
 
+// Mirrors the architectural-timer split between a raw counter and an
+// annotated, caller-selectable timestamp: each variant names a different
+// kernel clock that a raw kernel timestamp can be reported against.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum ClockSource {
+    /// Nanoseconds since boot, via `bpf_ktime_get_boot_ns`.
+    BootTime = 0,
+    /// Nanoseconds since an arbitrary monotonic start, via `bpf_ktime_get_ns`.
+    Monotonic = 1,
+    /// Wall-clock realtime, reconstructed as `bpf_ktime_get_boot_ns` plus `BOOT_TIME`.
+    RealTime = 2,
+}
+
+impl ClockSource {
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            1 => ClockSource::Monotonic,
+            2 => ClockSource::RealTime,
+            _ => ClockSource::BootTime,
+        }
+    }
+}
+
+fn clock_source() -> ClockSource {
+    ClockSource::from_bits(unsafe { core::ptr::read_volatile(&CLOCK_SOURCE) })
+}
+
+pub fn event_timestamp() -> u64 {
+    match clock_source() {
+        ClockSource::BootTime => unsafe { bpf_ktime_get_boot_ns() },
+        ClockSource::Monotonic => unsafe { bpf_ktime_get_ns() },
+        // CLOCK_BOOTTIME, not CLOCK_MONOTONIC: the latter freezes during
+        // suspend, so reconstructing realtime from it would drift backward
+        // by the suspend duration relative to the BootTime variant.
+        ClockSource::RealTime => unsafe { bpf_ktime_get_boot_ns() }.wrapping_add(boot_time()),
+    }
+}
+
 fn enabled_feature_flags() -> FeatureFlags {
     FeatureFlags::from_bits_truncate(unsafe { core::ptr::read_volatile(&FEATURE_FLAGS) })
 }
@@ -67,3 +269,129 @@ pub fn is_support_enabled(feature_flags: FeatureFlags, op_flags: OpFlags) -> boo
     let enabled_feature_flags = enabled_feature_flags();
     enabled_feature_flags.contains(feature_flags) && enabled_op_flags.contains(op_flags)
 }
+
+// One slot per bit in OpFlags, so each op gets its own sampling config/state.
+const MAX_OPS: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OpSamplingConfig {
+    /// Emit roughly 1-in-`sample_every` events that pass `is_support_enabled`;
+    /// 0 and 1 both mean "no ratio sampling".
+    pub sample_every: u32,
+    /// Tokens added to this op's bucket per second; 0 disables the token
+    /// bucket and only ratio sampling applies.
+    pub token_refill_rate: u32,
+    /// Maximum number of tokens the bucket can hold. Also disables the token
+    /// bucket when 0, the same as `token_refill_rate == 0`: otherwise every
+    /// refill would clamp `tokens` back down to 0 and silence the op for
+    /// good, with no ratio-sampling fallback.
+    pub token_bucket_capacity: u32,
+}
+
+#[derive(Copy, Clone)]
+struct OpSamplingState {
+    hit_count: u32,
+    tokens: u32,
+    last_refill_ns: u64,
+}
+
+#[map]
+// Userspace-writable sampling/rate-limit configuration, one slot per op.
+static OP_SAMPLING_CONFIG: Array<OpSamplingConfig> = Array::with_max_entries(MAX_OPS, 0);
+
+#[map]
+// Per-CPU so the sampling decision never touches another core's cacheline.
+static OP_SAMPLING_STATE: PerCpuArray<OpSamplingState> = PerCpuArray::with_max_entries(MAX_OPS, 0);
+
+#[map]
+// Per-CPU tally of events dropped by sampling/rate-limiting, for userspace to read.
+static DROPPED_EVENTS: PerCpuArray<u64> = PerCpuArray::with_max_entries(MAX_OPS, 0);
+
+// Maps a single-bit OpFlags value to its sampling slot. Returns None (and
+// the caller falls back to unconditional emission) for the flagless value,
+// whose bits().trailing_zeros() would otherwise land on MAX_OPS out of
+// range, and for multi-bit combinations, which would otherwise collapse
+// onto their lowest set bit and alias two distinct ops onto one slot.
+fn op_index(op_flags: OpFlags) -> Option<u32> {
+    if op_flags.bits().count_ones() != 1 {
+        return None;
+    }
+    let idx = op_flags.bits().trailing_zeros();
+    if idx >= MAX_OPS {
+        return None;
+    }
+    Some(idx)
+}
+
+fn record_drop(op_index: u32) {
+    if let Some(counter) = DROPPED_EVENTS.get_ptr_mut(op_index) {
+        unsafe { *counter += 1 };
+    }
+}
+
+// Refills the token bucket for elapsed whole seconds, then tries to take one
+// token. Returns false (and leaves the bucket untouched) when it's empty.
+fn take_token(state: &mut OpSamplingState, config: &OpSamplingConfig) -> bool {
+    let now = event_timestamp();
+    let elapsed_secs = now.saturating_sub(state.last_refill_ns) / 1_000_000_000;
+    if elapsed_secs > 0 {
+        let refilled = elapsed_secs.saturating_mul(config.token_refill_rate as u64);
+        state.tokens = (state.tokens as u64)
+            .saturating_add(refilled)
+            .min(config.token_bucket_capacity as u64) as u32;
+        // Carry the sub-second remainder forward instead of snapping to
+        // `now`, otherwise sparse/bursty arrivals lose up to ~1s of accrued
+        // time every refill and the bucket fills slower than configured.
+        state.last_refill_ns = state
+            .last_refill_ns
+            .wrapping_add(elapsed_secs * 1_000_000_000);
+    }
+
+    if state.tokens == 0 {
+        return false;
+    }
+    state.tokens -= 1;
+    true
+}
+
+/// Like `is_support_enabled`, but also applies per-op sampling and
+/// token-bucket rate-limiting on top, so operators can bound event volume
+/// under load without reloading the program.
+pub fn should_emit(feature_flags: FeatureFlags, op_flags: OpFlags) -> bool {
+    if !is_support_enabled(feature_flags, op_flags) {
+        return false;
+    }
+
+    let idx = match op_index(op_flags) {
+        Some(idx) => idx,
+        None => return true,
+    };
+    let config = match OP_SAMPLING_CONFIG.get(idx) {
+        Some(config) => config,
+        None => return true,
+    };
+    let state = match OP_SAMPLING_STATE.get_ptr_mut(idx) {
+        Some(state) => unsafe { &mut *state },
+        None => return true,
+    };
+
+    // Ratio sampling first: the token bucket must only be charged for events
+    // that are actually going to be emitted, not ones ratio sampling is
+    // about to drop anyway.
+    if config.sample_every > 1 {
+        state.hit_count = state.hit_count.wrapping_add(1);
+        if state.hit_count % config.sample_every != 0 {
+            record_drop(idx);
+            return false;
+        }
+    }
+
+    let token_bucket_enabled = config.token_refill_rate > 0 && config.token_bucket_capacity > 0;
+    if token_bucket_enabled && !take_token(state, config) {
+        record_drop(idx);
+        return false;
+    }
+
+    true
+}